@@ -5,6 +5,7 @@ use std::iter::Iterator;
 use std::convert::TryInto;
 use std::mem::swap;
 use rand::random;
+use noise::{NoiseFn, OpenSimplex, Seedable};
 
 type Vec2 = Vector2<f32>;
 type Loc2 = Vector2<isize>;
@@ -38,6 +39,25 @@ fn cap_vec(vec: Vec2) -> Vec2 {
     }
 }
 
+/// Evaluates an acceleration function `a(vel) -> dv/dt` at a given velocity.
+fn derivative(vel: Vec2, accel: &dyn Fn(Vec2) -> Vec2) -> Vec2 {
+    accel(vel)
+}
+
+/// Integrates `vel` forward by `dt` using classical 4th-order Runge-Kutta,
+/// given an acceleration function `accel(vel) -> dv/dt`.
+/// Stays stable for small `dt`. For a constant acceleration this equals a single
+/// Euler step of `dt`; for a velocity-dependent one it differs from Euler by terms
+/// of order `dt^2` and higher.
+fn rk4_integrate(vel: Vec2, dt: f32, accel: impl Fn(Vec2) -> Vec2) -> Vec2 {
+    let k1 = derivative(vel, &accel);
+    let k2 = derivative(vec2_add(vel, vec2_scale(k1, 0.5 * dt)), &accel);
+    let k3 = derivative(vec2_add(vel, vec2_scale(k2, 0.5 * dt)), &accel);
+    let k4 = derivative(vec2_add(vel, vec2_scale(k3, dt)), &accel);
+    let sum = vec2_add(vec2_add(k1, vec2_scale(k2, 2.0)), vec2_add(vec2_scale(k3, 2.0), k4));
+    vec2_add(vel, vec2_scale(sum, dt / 6.0))
+}
+
 fn grid_get<T>(grid: &Grid<T>, loc: Loc2) -> Option<&T>
   where T: std::clone::Clone
 {    
@@ -49,11 +69,32 @@ fn grid_get_mut<T>(grid: &mut Grid<T>, loc: Loc2) -> Option<&mut T>
 {
     grid.get_mut(loc[1].try_into().ok()?, loc[0].try_into().ok()?)
 }
+
+/// Side length, in cells, of the chunks used by `Board`'s sleeping-cell scheduler.
+const CHUNK_SIZE: usize = 16;
+/// Below this squared magnitude, a cell's velocity/motion counts as "at rest".
+const SLEEP_EPSILON: f32 = 1e-4;
+
+fn chunk_count(cells: usize) -> usize {
+    cells.div_ceil(CHUNK_SIZE)
+}
+
+/// Cell-index bounds `(x0, x1, y0, y1)` of the given chunk, clamped to the grid.
+fn chunk_bounds(chunk_x: usize, chunk_y: usize, cols: usize, rows: usize) -> (usize, usize, usize, usize) {
+    let x0 = chunk_x * CHUNK_SIZE;
+    let y0 = chunk_y * CHUNK_SIZE;
+    (x0, (x0 + CHUNK_SIZE).min(cols), y0, (y0 + CHUNK_SIZE).min(rows))
+}
+/// Temperature, in the same arbitrary units as `Cell::temperature`, above which
+/// Water transitions to Steam (and below which Steam condenses back to Water).
+const WATER_BOILING_POINT: f32 = 100.0;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
-enum Species {
+pub enum Species {
     Air,
     Sand,
     Water,
+    Steam,
 }
 
 impl Species {
@@ -64,6 +105,7 @@ impl Species {
             Species::Air   => ' ',
             Species::Sand  => '%',
             Species::Water => '.',
+            Species::Steam => '"',
         }
     }
 
@@ -74,6 +116,7 @@ impl Species {
             Species::Air   => 0.0,
             Species::Sand  => 0.2,
             Species::Water => 0.4,
+            Species::Steam => 0.3,
         }
     }
 
@@ -87,6 +130,7 @@ impl Species {
             Species::Air   => 0.1,
             Species::Sand  => 1.0,
             Species::Water => 0.7,
+            Species::Steam => 0.1,
         }
     }
 
@@ -99,6 +143,7 @@ impl Species {
             Species::Air   => 0.1,
             Species::Sand  => 2.5,
             Species::Water => 1.0, // 1.0g/(cm)^3
+            Species::Steam => 0.1,
         }
     }
 
@@ -111,6 +156,8 @@ impl Species {
             Species::Air   => [0.0,0.0],
             Species::Sand  => [0.0,1.9],
             Species::Water => [0.0,1.9],
+            // Steam is buoyant: it rises rather than falls.
+            Species::Steam => [0.0,-1.9],
         }
     }
 
@@ -121,6 +168,7 @@ impl Species {
             Species::Air   => 0.20,
             Species::Sand  => 0.08,
             Species::Water => 0.05,
+            Species::Steam => 0.20,
         }
     }
 
@@ -131,6 +179,28 @@ impl Species {
             Species::Air   => 0.8,
             Species::Sand  => 0.0,
             Species::Water => 0.2,
+            Species::Steam => 0.8,
+        }
+    }
+
+    /// How readily this material conducts heat into the neighboring cells it touches.
+    /// range: 0..1
+    fn get_conductivity(&self) -> f32 {
+        match self {
+            Species::Air   => 0.05,
+            Species::Sand  => 0.3,
+            Species::Water => 0.6,
+            Species::Steam => 0.1,
+        }
+    }
+
+    /// The species this material becomes once `temp` crosses a phase-transition
+    /// threshold, or `None` if this material is stable at `temp`.
+    fn transition(&self, temp: f32) -> Option<Species> {
+        match self {
+            Species::Water if temp >= WATER_BOILING_POINT => Some(Species::Steam),
+            Species::Steam if temp < WATER_BOILING_POINT => Some(Species::Water),
+            _ => None,
         }
     }
 }
@@ -147,7 +217,13 @@ struct Cell {
     velocity: Vec2,
     /// Motion is set to velocity at the start of the step, and depleted by the end of a step
     motion: Vec2,
+    /// Heat content, diffused by conduction and driving phase transitions.
+    temperature: f32,
     species: Species,
+    /// Set once `velocity` and `motion` have both stayed below `SLEEP_EPSILON`
+    /// for a step. Asleep cells are skipped by `gravity_step`, `velocity_step`,
+    /// and `motion_step` until something wakes them back up.
+    asleep: bool,
 }
 
 impl Cell {
@@ -156,9 +232,34 @@ impl Cell {
     }
 }
 
+/// Emitted by `Board::step` when two cells collide with enough velocity change
+/// to count as a meaningful impact, as opposed to routine settling.
+#[derive(Debug, Clone, Copy)]
+pub struct CollisionEvent {
+    pub loc: Loc2,
+    pub other: Loc2,
+    pub velocity_change: f32,
+    pub species: (Species, Species),
+}
+
 #[derive(Debug, Clone)]
 pub struct Board {
     grid: Grid<Cell>,
+    /// Simulation timestep used by the RK4 velocity integrator.
+    /// range: >0. At 1.0 gravity matches the old per-step Euler update exactly,
+    /// and friction approximates it (RK4 rather than a single Euler step).
+    dt: f32,
+    /// Minimum velocity-change magnitude, akin to a walking-speed cutoff, for a
+    /// collision to be reported as a `CollisionEvent`; routine settling stays below this.
+    collision_threshold: f32,
+    /// Collisions recorded so far this step, drained and returned by `step`.
+    collision_events: Vec<CollisionEvent>,
+    /// Grid dimensions, in `CHUNK_SIZE`-cell chunks.
+    chunk_cols: usize,
+    chunk_rows: usize,
+    /// Per-chunk flag: does this chunk contain awake cells that need processing?
+    /// Indexed by `chunk_y * chunk_cols + chunk_x`.
+    dirty_chunks: Vec<bool>,
 }
 
 // TODO: Board: have functions to get and set and such.
@@ -167,9 +268,96 @@ impl Board {
     /// Creates an empty board
     pub fn new(cols: usize, rows: usize) -> Self {
         let grid = Grid::new(rows, cols);
+        let chunk_cols = chunk_count(cols);
+        let chunk_rows = chunk_count(rows);
         Board{
             grid,
+            dt: 1.0,
+            collision_threshold: 3.0,
+            collision_events: Vec::new(),
+            chunk_cols,
+            chunk_rows,
+            // Every chunk starts dirty, so the first step processes the whole board.
+            dirty_chunks: vec![true; chunk_cols * chunk_rows],
+        }
+    }
+
+    /// Sets the simulation timestep used by the RK4 velocity integrator,
+    /// decoupling simulation rate from how often `step` is called.
+    pub fn set_dt(&mut self, dt: f32) {
+        self.dt = dt;
+    }
+
+    /// Sets the velocity-change magnitude above which a collision is reported
+    /// as a `CollisionEvent` from `step`.
+    pub fn set_collision_threshold(&mut self, threshold: f32) {
+        self.collision_threshold = threshold;
+    }
+
+    /// Marks the cell at `loc` awake and flags its chunk, plus the neighboring
+    /// chunks it could influence, dirty so the active phases revisit them.
+    fn wake(&mut self, loc: Loc2) {
+        match grid_get_mut(&mut self.grid, loc) {
+            Some(cell) => cell.asleep = false,
+            None => return,
+        }
+        self.mark_chunk_dirty(loc);
+        for n in neighbors(loc) {
+            self.mark_chunk_dirty(n);
+        }
+    }
+
+    fn mark_chunk_dirty(&mut self, loc: Loc2) {
+        if loc[0] < 0 || loc[1] < 0 { return; }
+        let (cx, cy) = (loc[0] as usize / CHUNK_SIZE, loc[1] as usize / CHUNK_SIZE);
+        if cx < self.chunk_cols && cy < self.chunk_rows {
+            self.dirty_chunks[cy * self.chunk_cols + cx] = true;
+        }
+    }
+
+    /// Is the chunk containing `loc` flagged as having awake cells?
+    fn is_chunk_dirty(&self, loc: Loc2) -> bool {
+        let (cx, cy) = (loc[0] as usize / CHUNK_SIZE, loc[1] as usize / CHUNK_SIZE);
+        self.dirty_chunks[cy * self.chunk_cols + cx]
+    }
+
+    /// Creates a board of layered terrain from 2D OpenSimplex noise. The species layout is
+    /// deterministic for a given `seed`; the small per-cell motion perturbation comes from
+    /// `rand::random` and is not.
+    /// A high-frequency channel carves a per-column surface height curve (Air above, Sand below),
+    /// and a second, lower-frequency channel carves basins in the Sand that get flooded with Water.
+    pub fn from_noise(cols: usize, rows: usize, seed: u64) -> Self {
+        let mut board = Board::new(cols, rows);
+
+        let surface_noise = OpenSimplex::new().set_seed(seed as u32);
+        let basin_noise = OpenSimplex::new().set_seed(seed.wrapping_add(1) as u32);
+        // Frequencies are in noise-space units per column/row.
+        let surface_freq = 0.1;
+        let basin_freq = 0.03;
+        let basin_threshold = -0.3;
+
+        for x in 0..cols {
+            let surface = surface_noise.get([x as f64 * surface_freq, 0.0]);
+            // Map noise's -1..1 range onto the middle half of the board's rows.
+            let surface_row = (rows as f64 * (0.5 + 0.25 * surface)) as isize;
+
+            for y in 0..rows {
+                let loc = [x as isize, y as isize];
+                let species = if (y as isize) < surface_row {
+                    Species::Air
+                } else {
+                    let basin = basin_noise.get([x as f64 * basin_freq, y as f64 * basin_freq]);
+                    if basin < basin_threshold { Species::Water } else { Species::Sand }
+                };
+                if let Some(cell) = grid_get_mut(&mut board.grid, loc) {
+                    cell.species = species;
+                    // Small perturbation, to prevent them from being perfect <3
+                    cell.motion = [random::<f32>() * 0.1 - 0.05, random::<f32>() * 0.1 - 0.05];
+                }
+            }
         }
+
+        board
     }
 
     /// Prints the board to stdout
@@ -187,34 +375,56 @@ impl Board {
         }
     }
     /// Single steps the simulation forward.
-    pub fn step(&mut self) {
+    /// Returns the high-energy collisions that occurred during this step.
+    pub fn step(&mut self) -> Vec<CollisionEvent> {
         self.gravity_step();
         for _ in 0..4 {
             self.velocity_step();
         }
+        self.temperature_step();
         self.copy_velocity_to_motion();
         for _ in 0..10 {
             if self.motion_step() { break; }
         }
+        self.update_sleep();
+        std::mem::take(&mut self.collision_events)
     }
     fn gravity_step(&mut self) {
         // This is related to the TODO on Species::get_gravity
         // Doing that would require seperating the calculation
         //   and application of gravity into two loops.
-        for cell in self.grid.iter_mut() {
-            cell.velocity = vec2_add(cell.velocity, cell.species.get_gravity());
-            //HACKHACK: Try to calm the sandstorm by slowing down the air
-            //if cell.species == Species::Air {
-            //    cell.velocity = vec2_scale(cell.velocity, 0.3);
-            //}
+        let cols = self.grid.cols();
+        let dt = self.dt;
+        // Cells are still visited in the original row-major order (just with
+        // sleeping regions skipped) so this produces identical results to the
+        // un-chunked sim for any cell that's actually processed.
+        for y in 0..self.grid.rows() {
+            for x in 0..cols {
+                let loc = [x as isize, y as isize];
+                if !self.is_chunk_dirty(loc) { continue; }
+                let cell = grid_get_mut(&mut self.grid, loc).unwrap();
+                if cell.asleep { continue; }
+                let gravity = cell.species.get_gravity();
+                cell.velocity = rk4_integrate(cell.velocity, dt, |_vel| gravity);
+                //HACKHACK: Try to calm the sandstorm by slowing down the air
+                //if cell.species == Species::Air {
+                //    cell.velocity = vec2_scale(cell.velocity, 0.3);
+                //}
+            }
         }
     }
     /// Resolves velocity
     fn velocity_step(&mut self) {
         let cols = self.grid.cols();
+        // Row-major traversal, same as the un-chunked sim, with sleeping
+        // regions skipped: this phase mutates cells in place and reads
+        // already-updated neighbor state within the pass, so changing the
+        // visitation order would change the result, not just the cost.
         for y in 0..self.grid.rows() {
             for x in 0..cols {
                 let loc = [x as isize, y as isize];
+                if !self.is_chunk_dirty(loc) { continue; }
+                if grid_get(&self.grid, loc).unwrap().asleep { continue; }
 
                 // 1. Calculate and apply pushing
                 let cell = grid_get(&self.grid, loc).unwrap();
@@ -236,6 +446,26 @@ impl Board {
                 let elasticity = cell.species.get_elasticity() * other.map(|o| o.species.get_elasticity()).unwrap_or(0.2);
                 let our_impact = vec2_scale(impact_dir, vec2_dot(impact_dir, vec2_sub(cell.velocity, system_velocity)) * -(1.0 + elasticity));
                 let their_impact = other.map(|other| vec2_scale(impact_dir, vec2_dot(impact_dir, vec2_sub(other.velocity, system_velocity)) * -(1.0 + elasticity))).unwrap_or_default();
+
+                let impact_mag = vec2_len(our_impact);
+                if impact_mag > self.collision_threshold {
+                    if let Some(other) = other {
+                        self.collision_events.push(CollisionEvent {
+                            loc,
+                            other: dest,
+                            velocity_change: impact_mag,
+                            species: (cell.species, other.species),
+                        });
+                    }
+                }
+                // A meaningful impact wakes both participants: the other cell
+                // may currently be asleep (e.g. a falling grain landing on a
+                // settled pile), and needs to rejoin the active phases.
+                if vec2_square_len(our_impact) > SLEEP_EPSILON || vec2_square_len(their_impact) > SLEEP_EPSILON {
+                    self.wake(loc);
+                    self.wake(dest);
+                }
+
                 let cell = grid_get_mut(&mut self.grid, loc).unwrap();
                 cell.velocity = vec2_add(cell.velocity, our_impact);
                 if let Some(other) = grid_get_mut(&mut self.grid, dest) {
@@ -243,15 +473,55 @@ impl Board {
                 }
 
                 // 2. Calculate and apply friction
-                let cell = grid_get_mut(&mut self.grid, loc).unwrap();
+                // Velocity-proportional friction (dv/dt = -friction_coeff*vel) is integrated
+                // with the same RK4 scheme as gravity, so the dissipation rate tracks `dt`
+                // instead of being tied to an implicit per-step Euler update.
+                let cell = grid_get(&self.grid, loc).unwrap();
                 let friction_coeff = cell.species.get_friction_coeff();
-                let heat = vec2_scale(cell.velocity, friction_coeff / 4.0 * cell.species.get_mass());
-                cell.velocity = vec2_scale(cell.velocity, 1.0 - friction_coeff);
+                let before = cell.velocity;
+                let after = rk4_integrate(before, self.dt, |vel| vec2_scale(vel, -friction_coeff));
+                let heat = vec2_len(vec2_scale(vec2_sub(before, after), 0.25 * cell.species.get_mass()));
+                let cell = grid_get_mut(&mut self.grid, loc).unwrap();
+                cell.velocity = after;
+                cell.temperature += heat;
+            }
+        }
+    }
+    /// Diffuses temperature between neighboring cells, then applies any
+    /// resulting phase transitions (e.g. Water boiling into Steam).
+    fn temperature_step(&mut self) {
+        let cols = self.grid.cols();
+        // Unlike gravity_step/velocity_step/motion_step, this phase is NOT
+        // gated by `asleep`/`dirty_chunks`: those flags only track velocity
+        // and motion settling, not thermal equilibrium, so skipping a cell
+        // here would freeze its temperature while its neighbors kept
+        // conducting toward it. Each cell updates only its own temperature,
+        // in place, so the total heat on the board is not exactly conserved.
+        for y in 0..self.grid.rows() {
+            for x in 0..cols {
+                let loc = [x as isize, y as isize];
+
+                // Conduction: T += k*(T_n - T) for each neighbor, weighted by the
+                // product of both cells' conductivity, with the total weight used
+                // clamped to 1 so the cell never loses more heat than it has.
+                let cell = grid_get(&self.grid, loc).unwrap();
+                let our_conductivity = cell.species.get_conductivity();
+                let our_temp = cell.temperature;
+                let mut delta = 0.0;
+                let mut weight_used = 0.0;
                 for n in neighbors(loc) {
-                    if let Some(other) = grid_get_mut(&mut self.grid, n){
-                        other.velocity = vec2_add(other.velocity, vec2_scale(heat, 1.0 / other.species.get_mass()));
+                    if let Some(other) = grid_get(&self.grid, n) {
+                        let k = (our_conductivity * other.species.get_conductivity()).min(1.0 - weight_used);
+                        delta += k * (other.temperature - our_temp);
+                        weight_used += k;
                     }
                 }
+
+                let cell = grid_get_mut(&mut self.grid, loc).unwrap();
+                cell.temperature += delta;
+                if let Some(new_species) = cell.species.transition(cell.temperature) {
+                    cell.species = new_species;
+                }
             }
         }
     }
@@ -274,10 +544,14 @@ impl Board {
         let cols = self.grid.cols();
         // Has no work been done yet?
         let mut no_work_done = true;
+        // Row-major traversal, same as the un-chunked sim (see `velocity_step`
+        // for why the visitation order matters here).
         for y in 0..self.grid.rows() {
             for x in 0..cols {
                 let loc = [x as isize, y as isize];
+                if !self.is_chunk_dirty(loc) { continue; }
                 let cell = grid_get(&self.grid, loc).unwrap();
+                if cell.asleep { continue; }
                 if vec2_square_len(cell.motion) < 1.0 { continue }
                 no_work_done = false;
                 let offset = velocity_to_offset(cell.motion);
@@ -292,27 +566,50 @@ impl Board {
                 }
                 let cell = grid_get_mut(&mut self.grid, loc).unwrap();
                 swap(&mut storage, cell);
+
+                // A cell actually moved: it (and whatever now occupies
+                // `dest`) may have entered a chunk that was asleep.
+                self.wake(loc);
+                self.wake(dest);
             }
         }
         no_work_done
     }
+
+    /// Puts cells whose velocity and motion have both settled below
+    /// `SLEEP_EPSILON` to sleep, then drops the dirty flag of any chunk left
+    /// with no awake cells. Only scans chunks that were dirty this step, since
+    /// those are the only ones that could have changed.
+    fn update_sleep(&mut self) {
+        let (cols, rows) = (self.grid.cols(), self.grid.rows());
+        for chunk_y in 0..self.chunk_rows {
+            for chunk_x in 0..self.chunk_cols {
+                let idx = chunk_y * self.chunk_cols + chunk_x;
+                if !self.dirty_chunks[idx] { continue; }
+                let (x0, x1, y0, y1) = chunk_bounds(chunk_x, chunk_y, cols, rows);
+                let mut any_awake = false;
+                for y in y0..y1 {
+                    for x in x0..x1 {
+                        let cell = grid_get_mut(&mut self.grid, [x as isize, y as isize]).unwrap();
+                        if vec2_square_len(cell.velocity) < SLEEP_EPSILON && vec2_square_len(cell.motion) < SLEEP_EPSILON {
+                            cell.asleep = true;
+                        }
+                        any_awake |= !cell.asleep;
+                    }
+                }
+                self.dirty_chunks[idx] = any_awake;
+            }
+        }
+    }
 }
 
 fn main() {
-    let mut board = Board::new(24, 32);
-    for cell in board.grid.iter_col_mut(8) {
-        cell.species = Species::Sand;
-    }
-    for cell in board.grid.iter_col_mut(12) {
-        cell.species = Species::Water;
-    }
-    for cell in board.grid.iter_mut() {
-        // Small perturbation, to prevent them from being perfect <3
-        cell.motion = [random::<f32>() * 0.1 - 0.05, random::<f32>() * 0.1 - 0.05];
-    }
+    let mut board = Board::from_noise(24, 32, random());
     loop {
         board.print();
-        board.step();
+        for event in board.step() {
+            println!("collision: {:?}", event);
+        }
         std::thread::sleep(std::time::Duration::from_millis(50));
         println!("------");
     }